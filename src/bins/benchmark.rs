@@ -51,7 +51,7 @@ fn thread_reader(world: rcu_gp::RcuCell<Node>, info: Arc<BenchmarkInfo>, id: u32
             std::thread::yield_now();
         } else if mode == 1 {
             iteration_count += 1;
-            let guard = world.read();
+            let guard = world.read().unwrap();
             for value in &guard.payload {
                 if id == *value {
                     hit += 1;
@@ -78,7 +78,7 @@ fn thread_writer(_world: rcu_gp::RcuCell<Node>, info: Arc<BenchmarkInfo>, vect_s
             std::thread::yield_now();
         } else if mode == 1 {
             let new_node = gen_node(vect_size);
-            _world.replace(new_node);
+            _world.replace(new_node).ok().unwrap();
             iteration_count += 1;
         } else{
             break;
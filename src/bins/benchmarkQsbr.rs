@@ -33,22 +33,26 @@ fn gen_node(size: i32) -> Node {
     return n;
 }
 
-fn thread_reader(world: rcu_qsbr::RcuQsbr<Node>, info: Arc<BenchmarkInfo>, id: u32) {
+fn thread_reader(mut world: rcu_qsbr::RcuQsbr<Node>, info: Arc<BenchmarkInfo>, id: u32) {
     let mut hit: i64 = 0;
     let mut iteration_count = 0;
     loop {
         let mode = info.flag.load(Ordering::SeqCst);
         if mode == 0  {
+            world.quiescent_state();
             std::thread::yield_now();
         } else if mode == 1 {
             iteration_count += 1;
 
-            let guard = world.read();
-            for value in &guard.payload {
-                if id == *value {
-                    hit += 1;
+            {
+                let guard = world.read();
+                for value in &guard.payload {
+                    if id == *value {
+                        hit += 1;
+                    }
                 }
             }
+            world.quiescent_state();
 
         } else {
             break;
@@ -90,16 +94,13 @@ pub fn benchmark_qsbr() {
     } {
         let now = Instant::now();
         let node: Node = gen_node(vector_size);
-        let shared: Arc<rcu_qsbr::RcuQsbrShared<Node>> = Arc::new(rcu_qsbr::RcuQsbrShared::new(
-            (N_WRITER+ N_READERS).try_into().unwrap(),
-            node,
-        ));
+        let mut tokens = rcu_qsbr::RcuQsbr::gen_tokens(N_WRITER + N_READERS, node);
 
         let mgn = Arc::new(BenchmarkInfo::new());
 
         let mut handles = vec![];
         for id in 0..N_READERS {
-            let wc = rcu_qsbr::RcuQsbr::new(shared.clone());
+            let wc = tokens.pop().unwrap();
             let m = mgn.clone();
             let handle: thread::JoinHandle<()> = thread::spawn(move || {
                 thread_reader(wc, m,id);
@@ -109,7 +110,7 @@ pub fn benchmark_qsbr() {
 
         for _id in 0..N_WRITER {
             let m = mgn.clone();
-            let wc = rcu_qsbr::RcuQsbr::new(shared.clone());
+            let wc = tokens.pop().unwrap();
             let handle = thread::spawn(move || {
                 thread_writer(wc,m,vector_size);
             });
@@ -46,7 +46,7 @@ fn thread_checker(world: rcu_gp::RcuCell<Node>, id: u32) {
     println!("checker Start id #{}", id);
     let mut last_value: i32 = -1;
     loop {
-        let guard = world.read();
+        let Some(guard) = world.read() else { break };
         let now_id = guard.id.load(Ordering::Acquire);
         if last_value == now_id {
             if guard.accept.load(Ordering::Acquire) == N_THREADS {
@@ -83,7 +83,7 @@ fn thread_checker(world: rcu_gp::RcuCell<Node>, id: u32) {
 fn thread_creator(_world: rcu_gp::RcuCell<Node>, tid:i32) {
     println!("Writer Start {}",tid);
     loop {
-        let read_lock = _world.read();
+        let Some(read_lock) = _world.read() else { break };
         if read_lock.reject.load(Ordering::Acquire) != 0 {
             let new_node = gen_node();
             let new_id = new_node.id.load(Ordering::Relaxed);
@@ -91,6 +91,7 @@ fn thread_creator(_world: rcu_gp::RcuCell<Node>, tid:i32) {
             match r {
                 rcu_gp::CasResult::Guard(_) => println!("tid{} publish id {}",tid,new_id),
                 rcu_gp::CasResult::Old(_) =>  println!("tid{} Failed to update Block {}, another thread has updated the old block.",tid,new_id),
+                rcu_gp::CasResult::Closed(_) => break,
             }
         } else if read_lock.accept.load(Ordering::Acquire) == N_THREADS {
             break;
@@ -0,0 +1,20 @@
+/*
+Errors shared by the GP based RCU flavors
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcuGpError {
+    SelfDeadlock, // the token would wait for its own read-side critical section
+}
+
+impl std::fmt::Display for RcuGpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RcuGpError::SelfDeadlock => write!(
+                f,
+                "synchronize_rcu() inside a read-side critical section of the same token would deadlock"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RcuGpError {}
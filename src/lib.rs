@@ -3,3 +3,8 @@ pub mod rcu_gp;
 pub mod rcu_qsbr;
 pub mod rcu_list;
 pub mod rcu_gp_ptr;
+
+mod error;
+mod owner;
+
+pub use error::RcuGpError;
@@ -0,0 +1,51 @@
+use std::cell::Cell;
+#[cfg(debug_assertions)]
+use std::thread::ThreadId;
+
+/*
+Keeps a token on one thread at a time, and counts the read guards of the token
+The token stays Send but is not Sync, so two threads can not share it by reference.
+In debug builds it also checks that the guards of a token are not used from two threads.
+A token can move between threads while no guard of it is alive.
+ */
+pub(crate) struct OwnerCheck {
+    depth: Cell<u32>, // the guards alive, the Cell keeps the token !Sync
+    #[cfg(debug_assertions)]
+    owner: Cell<Option<ThreadId>>, // the thread holding the guards
+}
+
+impl OwnerCheck {
+    pub(crate) fn new() -> Self {
+        OwnerCheck {
+            depth: Cell::new(0),
+            #[cfg(debug_assertions)]
+            owner: Cell::new(None),
+        }
+    }
+
+    // a guard of the token is created
+    pub(crate) fn enter(&self) {
+        let depth = self.depth.get();
+        #[cfg(debug_assertions)]
+        {
+            let current = std::thread::current().id();
+            if depth > 0 {
+                assert_eq!(self.owner.get(), Some(current), "RCU token used from two threads");
+            }
+            self.owner.set(Some(current));
+        }
+        self.depth.set(depth + 1);
+    }
+
+    // a guard of the token is dropped
+    pub(crate) fn exit(&self) {
+        #[cfg(debug_assertions)]
+        assert_eq!(self.owner.get(), Some(std::thread::current().id()), "RCU token used from two threads");
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    // how many guards of the token are alive, only the thread holding them can see more than 0
+    pub(crate) fn depth(&self) -> u32 {
+        self.depth.get()
+    }
+}
@@ -6,6 +6,10 @@ use std::sync::Arc;
 
 use std::sync::Mutex;
 
+use crate::owner::OwnerCheck;
+
+pub use crate::error::RcuGpError;
+
 /*
 The data structure for the protected data and shared RCU infomation
  */
@@ -52,8 +56,9 @@ impl<T> RcuGPShared<T> {
     }
 }
 
-unsafe impl<T> Send for RcuGPShared<T> {}
-unsafe impl<T> Sync for RcuGPShared<T> {}
+// readers on many threads share the data, and any thread may drop it
+unsafe impl<T: Send + Sync> Send for RcuGPShared<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuGPShared<T> {}
 
 /*
 The read Guard
@@ -68,6 +73,7 @@ pub enum CasResult<'a, T: 'a>
     Guard(RcuGpWriteGuard<'a,T>),
     Old(T),
 }
+
 impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
     // for normal reader
     pub fn new(lock: &'a RcuCell<T>, new_data: T) -> Self {
@@ -128,6 +134,14 @@ impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
         }
 
     }
+
+    // Get the old protected data, fails instead of waiting for a read guard of the same token
+    pub fn try_get_old(&mut self) -> Result<Option<T>, RcuGpError> {
+        if self.data.is_some() && self.inner_lock.in_read_section() {
+            return Err(RcuGpError::SelfDeadlock);
+        }
+        Ok(self.get_old())
+    }
 }
     // delete the old data if the get_old is not called
 impl<'a, T> Drop for RcuGpWriteGuard<'a, T> {
@@ -176,10 +190,19 @@ impl<'a, T> Drop for RcuGpReadGuard<'a, T> {
 }
 
 // The tokens for acessing the proteced data
+/// A token is Send but not Sync, each thread needs its own,
+/// and the protected data has to be Send and Sync as well
+///
+/// ```compile_fail,E0277
+/// let cell = rcu::rcu_gp::RcuCell::gen_tokens(1, std::rc::Rc::new(0u32)).pop().unwrap();
+/// std::thread::spawn(move || **cell.read());
+/// ```
 pub struct RcuCell<T> {
     thread_id: usize,
 
     global_info: Arc<RcuGPShared<T>>, // shared 
+
+    owner: OwnerCheck,
 }
 
 fn is_busy(ctr: &AtomicU32, global_ctr: u32) -> bool {
@@ -197,6 +220,7 @@ impl<'a,T> RcuCell<T> {
         return RcuCell {
             thread_id: tc as usize,
             global_info: shared,
+            owner: OwnerCheck::new(),
         };
     }
     // generate 'num' of RcuCell for the protected data
@@ -226,6 +250,7 @@ impl<'a,T> RcuCell<T> {
     
     fn read_lock(&self) {
         //println!("read");
+        self.owner.enter();
         let id = self.thread_id;
         let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
 
@@ -245,11 +270,18 @@ impl<'a,T> RcuCell<T> {
         smp_mb();
         let id = self.thread_id;
         let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
-        self.global_info.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst)
+        self.global_info.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst);
+        self.owner.exit();
+    }
+
+    // whether a read guard of this token is alive
+    fn in_read_section(&self) -> bool {
+        self.global_info.thread_ctr[self.thread_id].load(Ordering::Relaxed) & RCU_NEST_MASK != 0
     }
 
     fn synchronize_rcu(&self) {
         //println!("synchronize_rcu");
+        debug_assert!(!self.in_read_section(), "{}", RcuGpError::SelfDeadlock);
         smp_mb();
         {
             let _lg = self.global_info.data.lock().unwrap();
@@ -1,8 +1,13 @@
 
 use std::ops::Deref;
 use std::ptr::NonNull;
-use std::sync::atomic::{fence, AtomicPtr, AtomicU32, Ordering};
+use std::sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::owner::OwnerCheck;
+
+pub use crate::error::RcuGpError;
 
 use std::sync::Mutex;
 
@@ -16,11 +21,24 @@ struct RcuGPShared<T> {
 
     data_ptr: AtomicPtr<T>, // For reader and the shared ownership
     mtx: Mutex<()>,
+
+    gp_seq: AtomicU64, // odd while a grace period is running
+
+    padding: u32, // settings from RcuCellBuilder
+    wait: WaitStrategy,
+    reclaim: ReclaimMode,
+    stall_timeout: Option<(Duration, StallHandler)>,
+
+    deferred: Mutex<Vec<(GpCookie, Box<T>)>>, // old data waiting for ReclaimMode::Deferred
 }
 
 impl<T> Drop for RcuGPShared< T> {
     fn drop(&mut self) {
-       let _ = unsafe { Box::from_raw(self.data_ptr.load(Ordering::Acquire)) };
+        // null if the cell was closed or the data was taken by RcuCell::into_inner()
+        let ptr = self.data_ptr.load(Ordering::Acquire);
+        if !ptr.is_null() {
+            let _ = unsafe { Box::from_raw(ptr) };
+        }
     }
 }
 
@@ -40,10 +58,13 @@ const RCU_NEST_COUNT: u32 = 0x1;
 //Set to 16 to prevent false sharing and improve proformence
 const CACHE_RATE: u32 = 1;
 
+// Old data kept by ReclaimMode::Deferred before a writer waits for a grace period
+const RCU_DEFERRED_LIMIT: usize = 64;
+
 impl<T> RcuGPShared<T> {
-    pub fn new(count: u32, data: T) -> Self {
+    pub fn new(builder: &mut RcuCellBuilder<T>, data: T) -> Self {
         let mut my_vec = Vec::new();
-        for _r in 0..count * CACHE_RATE {
+        for _r in 0..builder.readers * builder.padding {
             my_vec.push(AtomicU32::new(0));
         }
         let bx: Box<T> = Box::new(data);
@@ -53,12 +74,118 @@ impl<T> RcuGPShared<T> {
             thread_ctr: my_vec,
             data_ptr: AtomicPtr::new(Box::<T>::into_raw(bx)),
             mtx: Mutex::new(()),
+            gp_seq: AtomicU64::new(0),
+            padding: builder.padding,
+            wait: builder.wait,
+            reclaim: builder.reclaim,
+            stall_timeout: builder.stall_timeout.take(),
+            deferred: Mutex::new(Vec::new()),
         };
     }
 }
 
-unsafe impl<T> Send for RcuGPShared<T> {}
-unsafe impl<T> Sync for RcuGPShared<T> {}
+// How a writer waits for the readers during a grace period
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitStrategy {
+    Spin,            // lowest latency, burns a core
+    Yield,           // the default
+    Sleep(Duration), // for writers that are not in a hurry
+}
+
+impl WaitStrategy {
+    fn pause(&self) {
+        match self {
+            WaitStrategy::Spin => std::hint::spin_loop(),
+            WaitStrategy::Yield => std::thread::yield_now(),
+            WaitStrategy::Sleep(d) => std::thread::sleep(*d),
+        }
+    }
+}
+
+// When the old data of a write guard is dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReclaimMode {
+    Inline,   // the write guard waits for a grace period when it drops, the default
+    Deferred, // the write guard queues the old data, it is dropped by later writers once its grace period is over
+}
+
+// Called by a writer when a grace period waited longer than the stall timeout
+// gets the index of the token that holds the grace period up and the timeout
+pub type StallHandler = Box<dyn Fn(u32, Duration) + Send + Sync>;
+
+/*
+Settings for creating RcuCell tokens, see RcuCell::builder()
+ */
+pub struct RcuCellBuilder<T> {
+    readers: u32,
+    padding: u32,
+    wait: WaitStrategy,
+    reclaim: ReclaimMode,
+    stall_timeout: Option<(Duration, StallHandler)>,
+    _data: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T> Default for RcuCellBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RcuCellBuilder<T> {
+    pub fn new() -> Self {
+        RcuCellBuilder {
+            readers: 1,
+            padding: CACHE_RATE,
+            wait: WaitStrategy::Yield,
+            reclaim: ReclaimMode::Inline,
+            stall_timeout: None,
+            _data: std::marker::PhantomData,
+        }
+    }
+
+    // number of tokens, each thread needs one
+    pub fn readers(mut self, num: u32) -> Self {
+        self.readers = num;
+        self
+    }
+
+    // counters per token, set to 16 to prevent false sharing
+    pub fn padding(mut self, padding: u32) -> Self {
+        assert!(padding > 0, "padding must be at least 1");
+        self.padding = padding;
+        self
+    }
+
+    pub fn wait_strategy(mut self, wait: WaitStrategy) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    pub fn reclaim(mut self, mode: ReclaimMode) -> Self {
+        self.reclaim = mode;
+        self
+    }
+
+    // call 'on_stall' when a grace period waits longer than 'timeout' for a reader
+    // it runs on the writer thread once per stalled reader, while the writer keeps waiting
+    pub fn stall_timeout<F>(mut self, timeout: Duration, on_stall: F) -> Self
+    where
+        F: Fn(u32, Duration) + Send + Sync + 'static,
+    {
+        self.stall_timeout = Some((timeout, Box::new(on_stall)));
+        self
+    }
+
+    // generate the tokens for the protected data
+    pub fn build(mut self, data: T) -> Vec<RcuCell<T>> {
+        let shared = Arc::new(RcuGPShared::new(&mut self, data));
+        (0..self.readers).map(|_| RcuCell::new(shared.clone())).collect()
+    }
+}
+
+// readers on many threads share the data, and any thread may drop it
+unsafe impl<T: Send + Sync> Send for RcuGPShared<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuGPShared<T> {}
 /*
 The read Guard
  */
@@ -66,27 +193,40 @@ pub struct RcuGpWriteGuard<'a, T: 'a> {
     inner_lock: &'a RcuCell<T>,
     data: Option<Box<T>>,
     is_unlocked: bool,
+    cookie: GpCookie,
 }
 
+/*
+A cookie for the polled grace-period API
+The grace period is over once gp_seq reaches 'seq'
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpCookie {
+    seq: u64,
+}
+
+
 pub enum CasResult<'a, T: 'a> 
 {
     Guard(RcuGpWriteGuard<'a,T>),
-    Old(T),
+    Old(T),    // another writer replaced the data first
+    Closed(T), // the cell was closed, retrying will not help
 }
 impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
-    // for normal writer
-    pub fn new(lock: &'a RcuCell<T>, new_data: T) -> Self {
-        //let mut mtx = lock.global_info.data.lock().unwrap();
+    // for normal writer, gives the new data back if the cell is closed
+    pub fn new(lock: &'a RcuCell<T>, new_data: T) -> Result<Self, T> {
         let bx: Box<T> = Box::new(new_data);
         let ptr = Box::<T>::into_raw(bx);
 
-        let old = lock.global_info.data_ptr.swap(ptr,Ordering::AcqRel);
-
-        return RcuGpWriteGuard {
-            inner_lock: lock,
-            data: Some(unsafe { Box::from_raw(old) }),
-            is_unlocked: false,
-        };
+        match lock.publish(ptr) {
+            Some(old) => Ok(RcuGpWriteGuard {
+                inner_lock: lock,
+                data: Some(unsafe { Box::from_raw(old) }),
+                is_unlocked: false,
+                cookie: lock.start_grace_period(),
+            }),
+            None => Err(*unsafe { Box::from_raw(ptr) }),
+        }
     }
 // for atomic writer
     pub fn cas(lock: &'a RcuCell<T>, new_data: T, rg: RcuGpReadGuard<'a,T>) -> CasResult<'a,T> {
@@ -109,20 +249,26 @@ impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
                 inner_lock: lock,
                 data: Some(unsafe { Box::from_raw(old_ptr) }),
                 is_unlocked: false,
+                cookie: lock.start_grace_period(),
             };
             return CasResult::Guard(t);},
 
-            Err(_) => {           
+            Err(current) => {           
                 let mut b= Some(unsafe { Box::from_raw(ptr) });
-                return CasResult::Old( *std::mem::take(&mut b).unwrap());}
+                let new_data = *std::mem::take(&mut b).unwrap();
+                if current.is_null() {
+                    CasResult::Closed(new_data)
+                } else {
+                    CasResult::Old(new_data)
+                }}
         }
         
     }
     // Get the old protected data
-    // this will result in a synchronize_rcu()
+    // this will result in a synchronize_rcu() if no grace period has passed yet
     pub fn get_old(&mut self) -> Option<T> {
         if self.data.is_some(){
-            self.inner_lock.synchronize_rcu();
+            self.inner_lock.cond_synchronize(self.cookie);
             self.is_unlocked = true;
             return Some(*std::mem::take(&mut self.data).unwrap());
         }
@@ -131,15 +277,159 @@ impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
         }
 
     }
+
+    // Get the old protected data, fails instead of waiting for a read guard of the same token
+    pub fn try_get_old(&mut self) -> Result<Option<T>, RcuGpError> {
+        if self.data.is_some() {
+            self.inner_lock.try_cond_synchronize(self.cookie)?;
+        }
+        Ok(self.get_old())
+    }
+
+    // Keep the old protected data without waiting for the grace period,
+    // so the writer can do other work before the old data is reclaimed
+    pub fn defer(mut self) -> RcuDeferred<'a, T> {
+        self.is_unlocked = true;
+        RcuDeferred {
+            inner_lock: self.inner_lock,
+            data: std::mem::take(&mut self.data),
+            cookie: self.cookie,
+        }
+    }
 }
     // delete the old data if the get_old is not called
 impl<'a, T> Drop for RcuGpWriteGuard<'a, T> {
     fn drop(&mut self) {
-        if self.is_unlocked == false {
-            self.inner_lock.synchronize_rcu();
+        if let Some(bx) = self.data.take() {
+            self.inner_lock.retire(self.cookie, bx);
+        }
+    }
+}
+
+/*
+The old data of a write guard, waiting for its grace period
+ */
+pub struct RcuDeferred<'a, T: 'a> {
+    inner_lock: &'a RcuCell<T>,
+    data: Option<Box<T>>,
+    cookie: GpCookie,
+}
+
+impl<'a, T: 'a> RcuDeferred<'a, T> {
+    pub fn cookie(&self) -> GpCookie {
+        self.cookie
+    }
+
+    // check whether the old data can be reclaimed, without blocking
+    pub fn is_ready(&self) -> bool {
+        self.inner_lock.poll_grace_period(self.cookie)
+    }
+
+    // Get the old protected data if its grace period is over
+    pub fn try_get_old(&mut self) -> Option<T> {
+        if self.data.is_some() && self.is_ready() {
+            return self.data.take().map(|bx| *bx);
+        }
+        None
+    }
+
+    // Get the old protected data
+    // this will result in a synchronize_rcu() if no grace period has passed yet
+    pub fn get_old(&mut self) -> Option<T> {
+        if self.data.is_some() {
+            self.inner_lock.cond_synchronize(self.cookie);
+        }
+        self.data.take().map(|bx| *bx)
+    }
+}
+
+impl<'a, T> Drop for RcuDeferred<'a, T> {
+    fn drop(&mut self) {
+        if let Some(bx) = self.data.take() {
+            self.inner_lock.retire(self.cookie, bx);
         }
     }
 }
+
+/*
+A pool of spare values for writers, so a replace does not need a fresh allocation.
+The replaced values are kept in the pool and are handed back to the writer after a
+grace period, where they can be refilled in place.
+With one spare this is double buffering, with two spares it is triple buffering.
+ */
+pub struct RcuPool<'a, T: 'a> {
+    inner_lock: &'a RcuCell<T>,
+    ready: Vec<Box<T>>,   // no reader can see these
+    retired: Vec<Box<T>>, // readers may still use these
+    cookie: Option<GpCookie>, // taken after the last value was retired
+}
+
+impl<'a, T: 'a> RcuPool<'a, T> {
+    pub fn new(lock: &'a RcuCell<T>, spares: Vec<T>) -> Self {
+        assert!(!spares.is_empty(), "RcuPool needs at least one spare value");
+        RcuPool {
+            inner_lock: lock,
+            ready: spares.into_iter().map(Box::new).collect(),
+            retired: Vec::new(),
+            cookie: None,
+        }
+    }
+
+    // refill a spare value with 'fill' and publish it
+    // when no spare is ready, this waits for one grace period and
+    // recycles all the values retired so far
+    // returns false if the cell is closed, the value is kept as a spare
+    pub fn replace_recycled<F: FnOnce(&mut T)>(&mut self, fill: F) -> bool {
+        if self.ready.is_empty() {
+            self.recycle();
+        }
+        let mut bx = self.ready.pop().unwrap();
+        fill(&mut bx);
+        let ptr = Box::<T>::into_raw(bx);
+
+        match self.inner_lock.publish(ptr) {
+            Some(old) => {
+                self.retired.push(unsafe { Box::from_raw(old) });
+                self.cookie = Some(self.inner_lock.start_grace_period());
+                true
+            }
+            None => {
+                self.ready.push(unsafe { Box::from_raw(ptr) });
+                false
+            }
+        }
+    }
+
+    fn recycle(&mut self) {
+        if let Some(cookie) = self.cookie.take() {
+            self.inner_lock.cond_synchronize(cookie);
+            self.ready.append(&mut self.retired);
+        }
+    }
+
+    // number of values owned by the pool
+    pub fn len(&self) -> usize {
+        self.ready.len() + self.retired.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // get all the values owned by the pool
+    // this will result in a synchronize_rcu() if a value was retired
+    pub fn into_inner(mut self) -> Vec<T> {
+        self.recycle();
+        std::mem::take(&mut self.ready).into_iter().map(|bx| *bx).collect()
+    }
+}
+
+// the retired values can only be dropped after a grace period
+impl<'a, T> Drop for RcuPool<'a, T> {
+    fn drop(&mut self) {
+        self.recycle();
+    }
+}
 // reader guard 
 pub struct RcuGpReadGuard<'a, T: 'a> {
     data: NonNull<T>,
@@ -149,14 +439,14 @@ pub struct RcuGpReadGuard<'a, T: 'a> {
 }
 
 impl<'a, T: 'a> RcuGpReadGuard<'a, T> {
-     // lock the lock and create an read guard 
-    pub fn new(lock: &'a RcuCell<T>) -> Self {
+     // lock the lock and create an read guard, None if the cell is closed
+    pub fn new(lock: &'a RcuCell<T>) -> Option<Self> {
         let ptr = lock.global_info.data_ptr.load(Ordering::Acquire);
-        return RcuGpReadGuard {
-            data: unsafe { NonNull::new_unchecked(ptr) },
+        NonNull::new(ptr).map(|data| RcuGpReadGuard {
+            data,
             inner_lock: lock,
             cas_ptr: ptr,
-        };
+        })
     }
 }
 
@@ -178,10 +468,33 @@ impl<'a, T> Drop for RcuGpReadGuard<'a, T> {
 }
 
 // The token for acessing the proteced data
+/// A token is Send but not Sync, each thread needs its own
+///
+/// ```compile_fail,E0277
+/// let cell = rcu::rcu_gp_ptr::RcuCell::gen_tokens(1, 0u32).pop().unwrap();
+/// std::thread::scope(|s| {
+///     s.spawn(|| *cell.read().unwrap());
+///     s.spawn(|| *cell.read().unwrap());
+/// });
+/// ```
+///
+/// The protected data has to be Send and Sync as well
+///
+/// ```compile_fail,E0277
+/// let cell = rcu::rcu_gp_ptr::RcuCell::gen_tokens(1, std::rc::Rc::new(0u32)).pop().unwrap();
+/// std::thread::spawn(move || **cell.read().unwrap());
+/// ```
+///
+/// ```compile_fail,E0277
+/// let cell = rcu::rcu_gp_ptr::RcuCell::gen_tokens(1, std::cell::Cell::new(0u32)).pop().unwrap();
+/// std::thread::spawn(move || cell.read().unwrap().get());
+/// ```
 pub struct RcuCell<T> {
     thread_id: usize,
 
     global_info: Arc<RcuGPShared<T>>,
+
+    owner: OwnerCheck,
 }
 
 fn is_busy(ctr: &AtomicU32, global_ctr: u32) -> bool {
@@ -192,46 +505,109 @@ fn is_busy(ctr: &AtomicU32, global_ctr: u32) -> bool {
 impl<'a,T> RcuCell<T> {
     // user can not use this one
     fn new(shared: Arc<RcuGPShared<T>>) -> Self {
-        let tc = shared.thread_counter.fetch_add(1, Ordering::SeqCst) * CACHE_RATE;
+        let tc = shared.thread_counter.fetch_add(1, Ordering::SeqCst) * shared.padding;
 
         return RcuCell {
             thread_id: tc as usize,
             global_info: shared,
+            owner: OwnerCheck::new(),
         };
     }
 
      // generate 'num' of RcuCell for the protected data
     pub fn gen_tokens(num: u32, data: T) -> Vec<Self> {
-        let shared = Arc::new(RcuGPShared::new(num, data));
+        Self::builder().readers(num).build(data)
+    }
 
-        let mut r = Vec::new();
-        let mut c: u32 = 0;
-        while c < num {
-            r.push(Self::new(shared.clone()));
-            c += 1;
-        }
-        return r;
+    // configure the tokens before generating them
+    pub fn builder() -> RcuCellBuilder<T> {
+        RcuCellBuilder::new()
     }
 
-     // create a read guard
-    pub fn read(&self) -> RcuGpReadGuard<'_, T> {
+     // create a read guard, None if the cell is closed
+    pub fn read(&self) -> Option<RcuGpReadGuard<'_, T>> {
         self.read_lock();
-
-        return RcuGpReadGuard::new(self);
+        let guard = RcuGpReadGuard::new(self);
+        if guard.is_none() {
+            self.read_unlock();
+        }
+        guard
     }
 
-    // modify the proteced data
-    pub fn replace(&self, new_data: T) -> RcuGpWriteGuard<'_, T> {
+    // modify the proteced data, gives the new data back if the cell is closed
+    pub fn replace(&self, new_data: T) -> Result<RcuGpWriteGuard<'_, T>, T> {
         //println!("ptr");
         return RcuGpWriteGuard::new(self, new_data);
     }
+
+    // close the cell, later read() and replace() calls are rejected
+    // the final value is given back after one synchronize_rcu(),
+    // None if the cell was already closed
+    pub fn close(&self) -> Option<T> {
+        let old = self.global_info.data_ptr.swap(std::ptr::null_mut(), Ordering::AcqRel);
+        if old.is_null() {
+            return None;
+        }
+        self.synchronize_rcu();
+        Some(*unsafe { Box::from_raw(old) })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.global_info.data_ptr.load(Ordering::Acquire).is_null()
+    }
+
+    // swap in the new data, None if the cell is closed
+    fn publish(&self, ptr: *mut T) -> Option<*mut T> {
+        let mut old = self.global_info.data_ptr.load(Ordering::Acquire);
+        loop {
+            if old.is_null() {
+                return None;
+            }
+            match self.global_info.data_ptr.compare_exchange_weak(old, ptr, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(old),
+                Err(current) => old = current,
+            }
+        }
+    }
     
     pub fn atomic_replace (& 'a self, new_data: T, rg: RcuGpReadGuard<'a,T>) -> CasResult<'a,T>
     {
         return RcuGpWriteGuard::cas(self, new_data, rg);
     }
+
+    // get the protected data back, this only works for the last token of an open cell
+    // no reader can exist anymore, so no grace period is needed
+    pub fn into_inner(self) -> Result<T, Self> {
+        let RcuCell { thread_id, global_info, owner } = self;
+        let global_info = match Arc::try_unwrap(global_info) {
+            Ok(shared) => {
+                let ptr = shared.data_ptr.swap(std::ptr::null_mut(), Ordering::Acquire);
+                if !ptr.is_null() {
+                    return Ok(*unsafe { Box::from_raw(ptr) });
+                }
+                Arc::new(shared)
+            }
+            Err(global_info) => global_info,
+        };
+        Err(RcuCell {
+            thread_id,
+            global_info,
+            owner,
+        })
+    }
+
+    // access the protected data directly, this only works for the last token of an open cell
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.global_info).and_then(|shared| unsafe { shared.data_ptr.get_mut().as_mut() })
+    }
+
+    // create a pool of spare values for replace_recycled()
+    pub fn pool(&self, spares: Vec<T>) -> RcuPool<'_, T> {
+        RcuPool::new(self, spares)
+    }
     fn read_lock(&self) {
         //println!("read");
+        self.owner.enter();
         let id = self.thread_id;
         let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
 
@@ -251,17 +627,113 @@ impl<'a,T> RcuCell<T> {
         smp_mb();
         let id = self.thread_id;
         let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
-        self.global_info.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst)
+        self.global_info.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst);
+        self.owner.exit();
+    }
+
+    // get a cookie for the grace period that starts now
+    pub fn start_grace_period(&self) -> GpCookie {
+        smp_mb();
+        let seq = self.global_info.gp_seq.load(Ordering::SeqCst);
+        // a running grace period may have started too early, wait for the next one
+        GpCookie { seq: (seq + 3) & !1 }
+    }
+
+    // check whether the grace period of 'cookie' is over, without blocking
+    pub fn poll_grace_period(&self, cookie: GpCookie) -> bool {
+        smp_mb();
+        if self.global_info.gp_seq.load(Ordering::SeqCst) >= cookie.seq {
+            return true;
+        }
+        // no reader is inside a read-side critical section
+        let idle = self.global_info.thread_ctr.iter().all(|ctr| ctr.load(Ordering::SeqCst) & RCU_NEST_MASK == 0);
+        smp_mb();
+        idle
+    }
+
+    // only call synchronize_rcu() if the grace period of 'cookie' is not over
+    pub fn cond_synchronize(&self, cookie: GpCookie) {
+        if !self.poll_grace_period(cookie) {
+            self.synchronize_rcu();
+        }
+    }
+
+    // cond_synchronize(), fails instead of waiting for a read guard of this token
+    pub fn try_cond_synchronize(&self, cookie: GpCookie) -> Result<(), RcuGpError> {
+        if self.poll_grace_period(cookie) {
+            return Ok(());
+        }
+        self.try_synchronize_rcu()
+    }
+
+    // wait for a grace period, fails instead of waiting for a read guard of this token
+    pub fn try_synchronize_rcu(&self) -> Result<(), RcuGpError> {
+        if self.in_read_section() {
+            return Err(RcuGpError::SelfDeadlock);
+        }
+        self.synchronize_rcu();
+        Ok(())
+    }
+
+    // whether a read guard of this token is alive
+    fn in_read_section(&self) -> bool {
+        self.global_info.thread_ctr[self.thread_id].load(Ordering::Relaxed) & RCU_NEST_MASK != 0
+    }
+
+    // drop the old data once its grace period is over
+    fn retire(&self, cookie: GpCookie, data: Box<T>) {
+        match self.global_info.reclaim {
+            ReclaimMode::Inline => {
+                self.cond_synchronize(cookie);
+                drop(data);
+            }
+            ReclaimMode::Deferred => {
+                let pending = {
+                    let mut deferred = self.global_info.deferred.lock().unwrap();
+                    deferred.push((cookie, data));
+                    deferred.len()
+                };
+                if pending >= RCU_DEFERRED_LIMIT {
+                    self.flush_deferred();
+                } else {
+                    self.reclaim_deferred();
+                }
+            }
+        }
+    }
+
+    // drop the queued old data whose grace period is over, without blocking
+    fn reclaim_deferred(&self) {
+        let done: Vec<(GpCookie, Box<T>)> = {
+            let mut deferred = self.global_info.deferred.lock().unwrap();
+            let (done, pending) = std::mem::take(&mut *deferred)
+                .into_iter()
+                .partition(|(cookie, _)| self.poll_grace_period(*cookie));
+            *deferred = pending;
+            done
+        };
+        drop(done);
+    }
+
+    // wait for a grace period and drop all the old data queued by ReclaimMode::Deferred
+    pub fn flush_deferred(&self) {
+        let queued = std::mem::take(&mut *self.global_info.deferred.lock().unwrap());
+        if !queued.is_empty() {
+            self.synchronize_rcu();
+        }
     }
 
     fn synchronize_rcu(&self) {
         //println!("synchronize_rcu");
+        debug_assert!(!self.in_read_section(), "{}", RcuGpError::SelfDeadlock);
         smp_mb();
         {
             let _lg = self.global_info.mtx.lock().unwrap();
+            self.global_info.gp_seq.fetch_add(1, Ordering::SeqCst);
             self.update_counter_and_wait();
             barrier();
             self.update_counter_and_wait();
+            self.global_info.gp_seq.fetch_add(1, Ordering::SeqCst);
         }
         smp_mb();
     }
@@ -275,9 +747,17 @@ impl<'a,T> RcuCell<T> {
         barrier();
         let mut count = 0;
         for ctr in &self.global_info.thread_ctr {
-            if count % CACHE_RATE == 0 {
+            if count % self.global_info.padding == 0 {
+                let start = Instant::now();
+                let mut stalled = false;
                 while is_busy(ctr, new_value) {
-                    std::thread::yield_now();
+                    if let Some((timeout, on_stall)) = &self.global_info.stall_timeout {
+                        if !stalled && start.elapsed() > *timeout {
+                            on_stall(count / self.global_info.padding, *timeout);
+                            stalled = true;
+                        }
+                    }
+                    self.global_info.wait.pause();
                 }
             }
             count += 1;
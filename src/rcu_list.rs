@@ -1,29 +1,131 @@
-use std::cell::UnsafeCell;
+use std::cell::Cell;
 use std::collections::LinkedList;
+use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ptr::{null, NonNull};
-use std::sync::atomic::{fence, AtomicPtr, AtomicU32, Ordering};
+use std::sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
+use crate::owner::OwnerCheck;
+use crate::rcu_qsbr::QsbrState;
+
+pub use crate::error::RcuGpError;
+
+// The list is circular, 'head' marks the sentinel node which holds no data
+// A node can be seen by the readers of the list versions in [birth, death)
 pub struct LinkedNode<T> {
-    data: UnsafeCell<T>,
+    data: Option<T>,
     next: AtomicPtr<LinkedNode<T>>,
     prev: AtomicPtr<LinkedNode<T>>,
     head: bool,
+
+    birth: AtomicU64,
+    death: AtomicU64,
 }
 
-pub struct RcuGPShared<T> {
-    thread_counter: AtomicU32,
+// death of a node which is not removed by an atomic write guard
+const RCU_NODE_ALIVE: u64 = u64::MAX;
+
+impl<T> LinkedNode<T> {
+    // create a node which is not linked yet
+    fn alloc(data: Option<T>, next: *mut LinkedNode<T>, prev: *mut LinkedNode<T>, birth: u64) -> *mut LinkedNode<T> {
+        let node = Box::new(LinkedNode {
+            head: data.is_none(),
+            data,
+            next: AtomicPtr::new(next),
+            prev: AtomicPtr::new(prev),
+            birth: AtomicU64::new(birth),
+            death: AtomicU64::new(RCU_NODE_ALIVE),
+        });
+        Box::<LinkedNode<T>>::into_raw(node)
+    }
 
-    global_ctr: AtomicU32,
+    fn visible(&self, version: u64) -> bool {
+        self.birth.load(Ordering::Relaxed) <= version && version < self.death.load(Ordering::Relaxed)
+    }
+}
 
-    thread_ctr: Vec<AtomicU32>,
+// the next node a reader of 'version' can see, the sentinel head at the end of the list
+fn next_visible<T>(node: *mut LinkedNode<T>, version: u64) -> *mut LinkedNode<T> {
+    let mut ptr = unsafe { node.as_ref().unwrap().next.load(Ordering::Acquire) };
+    while !unsafe { ptr.as_ref().unwrap().visible(version) } {
+        ptr = unsafe { ptr.as_ref().unwrap().next.load(Ordering::Acquire) };
+    }
+    ptr
+}
+
+// the previous node a reader of 'version' can see, the sentinel head at the start of the list
+fn prev_visible<T>(node: *mut LinkedNode<T>, version: u64) -> *mut LinkedNode<T> {
+    let mut ptr = unsafe { node.as_ref().unwrap().prev.load(Ordering::Acquire) };
+    while !unsafe { ptr.as_ref().unwrap().visible(version) } {
+        ptr = unsafe { ptr.as_ref().unwrap().prev.load(Ordering::Acquire) };
+    }
+    ptr
+}
+
+// link a new node after 'prev'
+// the node is fully built before it is published, so readers see either the old or the new list
+fn insert_after<T>(prev: *mut LinkedNode<T>, data: T, birth: u64) -> *mut LinkedNode<T> {
+    let prev_ref = unsafe { prev.as_ref().unwrap() };
+    let next = prev_ref.next.load(Ordering::Acquire);
+    let new_ptr = LinkedNode::alloc(Some(data), next, prev, birth);
+    prev_ref.next.store(new_ptr, Ordering::Release);
+    unsafe { next.as_ref().unwrap().prev.store(new_ptr, Ordering::Release) };
+    new_ptr
+}
+
+// unlink a node, it keeps its own links so a reader parked on it can still move on
+fn unlink<T>(node: *mut LinkedNode<T>) {
+    let node_ref = unsafe { node.as_ref().unwrap() };
+    let next = node_ref.next.load(Ordering::Acquire);
+    let prev = node_ref.prev.load(Ordering::Acquire);
+    unsafe { prev.as_ref().unwrap().next.store(next, Ordering::Release) };
+    unsafe { next.as_ref().unwrap().prev.store(prev, Ordering::Release) };
+}
+
+// A chain of nodes which is not linked into a list yet, first and last node
+type Chain<T> = (*mut LinkedNode<T>, *mut LinkedNode<T>);
+
+fn build_chain<T>(data: LinkedList<T>, birth: u64) -> Option<Chain<T>> {
+    let mut first: *mut LinkedNode<T> = std::ptr::null_mut();
+    let mut last: *mut LinkedNode<T> = std::ptr::null_mut();
+    for item in data {
+        let new_ptr = LinkedNode::alloc(Some(item), std::ptr::null_mut(), last, birth);
+        if last.is_null() {
+            first = new_ptr;
+        } else {
+            unsafe { last.as_ref().unwrap().next.store(new_ptr, Ordering::Relaxed) };
+        }
+        last = new_ptr;
+    }
+    if first.is_null() {
+        None
+    } else {
+        Some((first, last))
+    }
+}
+
+// link a whole chain after 'prev'
+// the chain is published with one pointer store, so readers see all of it or none of it
+fn splice_after<T>(prev: *mut LinkedNode<T>, (first, last): Chain<T>) {
+    let prev_ref = unsafe { prev.as_ref().unwrap() };
+    let next = prev_ref.next.load(Ordering::Acquire);
+    unsafe { first.as_ref().unwrap().prev.store(prev, Ordering::Relaxed) };
+    unsafe { last.as_ref().unwrap().next.store(next, Ordering::Relaxed) };
+    prev_ref.next.store(first, Ordering::Release);
+    unsafe { next.as_ref().unwrap().prev.store(last, Ordering::Release) };
+}
+
+pub struct RcuGPShared<T, F: RcuFlavor = GpFlavor> {
+    rcu: F, // the reader slots and grace periods
 
     data_ptr: AtomicPtr<LinkedNode<T>>,
 
-    data: Mutex<i32>,
+    writer: Mutex<()>, // only one write guard at a time
+
+    version: AtomicU64, // bumped by every atomic write guard
 }
 
 fn barrier() {
@@ -39,108 +141,208 @@ const RCU_NEST_COUNT: u32 = 0x1;
 
 const CACHE_RATE: u32 = 1;
 
-impl<T> RcuGPShared<T> {
-    pub fn new(count: u32, mut data: LinkedList<T>) -> Self {
+// the list relies on the flavors for memory safety, so only this crate can implement them
+mod sealed {
+    pub trait Sealed {}
+}
+
+/*
+The RCU scheme under a list, each token owns one reader slot
+GpFlavor takes a counter on every read, QsbrFlavor reads for free but its
+tokens have to report quiescent states
+ */
+pub trait RcuFlavor: sealed::Sealed + Send + Sync {
+    fn new(count: u32) -> Self;
+    // claim a reader slot for a new token
+    fn register(&self) -> usize;
+    fn unregister(&self, _id: usize) {}
+    // 'nest' is the number of read guards of the token alive, 'online' whether it is online
+    // both are kept in the token, GpFlavor tokens are always online
+    fn read_lock(&self, id: usize, nest: u32, online: bool);
+    fn read_unlock(&self, id: usize);
+    fn synchronize_rcu(&self, id: usize, nest: u32);
+    // run 'f' which may block on another token, without holding up its grace periods
+    fn blocking<R, B: FnOnce() -> R>(&self, _id: usize, _nest: u32, f: B) -> R {
+        f()
+    }
+}
+
+
+pub struct GpFlavor {
+    thread_counter: AtomicU32,
+
+    global_ctr: AtomicU32,
+
+    thread_ctr: Vec<AtomicU32>,
+
+    data: Mutex<i32>,
+}
+
+impl sealed::Sealed for GpFlavor {}
+
+impl RcuFlavor for GpFlavor {
+    fn new(count: u32) -> Self {
         let mut my_vec = Vec::new();
         for _r in 0..count * CACHE_RATE {
             my_vec.push(AtomicU32::new(0));
         }
-        let mut head_ptr: *mut LinkedNode<T> = std::ptr::null_mut::<LinkedNode<T>>();
-        let mut prev_ptr: *mut LinkedNode<T> = std::ptr::null_mut::<LinkedNode<T>>();
-        if data.is_empty() == false {
-            let bk = data.pop_front();
-            let node = Box::new(LinkedNode {
-                data: bk.unwrap().into(),
-                next: AtomicPtr::new(std::ptr::null_mut::<LinkedNode<T>>()),
-                prev: AtomicPtr::new(std::ptr::null_mut::<LinkedNode<T>>()),
-                head: true,
-            });
-            head_ptr = Box::<LinkedNode<T>>::into_raw(node);
-            prev_ptr = head_ptr;
-            unsafe {
-                head_ptr
-                    .as_mut()
-                    .unwrap()
-                    .next
-                    .store(head_ptr, Ordering::Relaxed)
-            };
-            unsafe {
-                head_ptr
-                    .as_mut()
-                    .unwrap()
-                    .prev
-                    .store(head_ptr, Ordering::Relaxed)
-            };
-        }
-        while data.is_empty() == false {
-            let bk = data.pop_front();
-            let node = Box::new(LinkedNode {
-                data: bk.unwrap().into(),
-                next: AtomicPtr::new(head_ptr),
-                prev: AtomicPtr::new(prev_ptr),
-                head: false,
-            });
-            let new_ptr = Box::<LinkedNode<T>>::into_raw(node);
-            unsafe {
-                head_ptr
-                    .as_mut()
-                    .unwrap()
-                    .prev
-                    .store(new_ptr, Ordering::Relaxed)
-            };
-            unsafe {
-                prev_ptr
-                    .as_mut()
-                    .unwrap()
-                    .next
-                    .store(new_ptr, Ordering::Relaxed)
-            };
-            prev_ptr = new_ptr;
-        }
-        return RcuGPShared {
+        GpFlavor {
             thread_counter: AtomicU32::new(0),
             global_ctr: AtomicU32::new(0),
             thread_ctr: my_vec,
-            data_ptr: AtomicPtr::new(head_ptr),
             data: Mutex::new(1),
+        }
+    }
+
+    fn register(&self) -> usize {
+        (self.thread_counter.fetch_add(1, Ordering::SeqCst) * CACHE_RATE) as usize
+    }
+
+    fn read_lock(&self, id: usize, _nest: u32, _online: bool) {
+        //println!("read");
+        let temp_local = self.thread_ctr[id].load(Ordering::Acquire);
+
+        if (temp_local & RCU_NEST_MASK) == 0 {
+            let global = self.global_ctr.load(Ordering::Acquire);
+            self.thread_ctr[id].store(global + RCU_NEST_COUNT, Ordering::SeqCst);
+
+            smp_mb();
+        } else {
+            self.thread_ctr[id].store(temp_local + RCU_NEST_COUNT, Ordering::Relaxed)
+            //rlocal_ctr[id].store(global_ctr.read(Ordering::Acquire),Ordering::Release );
+        }
+    }
+
+    fn read_unlock(&self, id: usize) {
+        //println!("read unlock");
+        smp_mb();
+        let temp_local = self.thread_ctr[id].load(Ordering::Acquire);
+        self.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst)
+    }
+
+    fn synchronize_rcu(&self, _id: usize, nest: u32) {
+        //println!("synchronize_rcu");
+        debug_assert!(nest == 0, "{}", RcuGpError::SelfDeadlock);
+        smp_mb();
+        {
+            let _lg = self.data.lock().unwrap();
+            self.update_counter_and_wait();
+            barrier();
+            self.update_counter_and_wait();
+        }
+        smp_mb();
+    }
+}
+
+impl GpFlavor {
+    fn update_counter_and_wait(&self) {
+        let old_value: u32 = self.global_ctr.load(Ordering::Acquire);
+        let new_value: u32 = old_value ^ RCU_GP_CTR_PHASE;
+        self.global_ctr
+            .store(new_value, Ordering::Release);
+        barrier();
+        let mut count = 0;
+        for ctr in &self.thread_ctr {
+            if count % CACHE_RATE == 0 {
+                while is_busy(ctr, new_value) {
+                    std::thread::yield_now();
+                }
+            }
+            count += 1;
+        }
+    }
+}
+
+// The QSBR of rcu_qsbr, a token must not hold a read guard when it writes or goes offline
+pub struct QsbrFlavor {
+    qsbr: QsbrState,
+}
+
+impl sealed::Sealed for QsbrFlavor {}
+
+impl RcuFlavor for QsbrFlavor {
+    fn new(count: u32) -> Self {
+        QsbrFlavor {
+            qsbr: QsbrState::new(count as usize),
+        }
+    }
+
+    fn register(&self) -> usize {
+        self.qsbr.alloc_slot().expect("no free reader slot in the list")
+    }
+
+    fn unregister(&self, id: usize) {
+        self.qsbr.free_slot(id);
+    }
+
+    fn read_lock(&self, id: usize, nest: u32, online: bool) {
+        self.qsbr.read_lock(id, nest, online);
+    }
+
+    // reads are free, nothing to undo
+    fn read_unlock(&self, _id: usize) {}
+
+    fn synchronize_rcu(&self, id: usize, nest: u32) {
+        self.qsbr.synchronize_rcu(id, nest);
+    }
+
+    // go offline while 'f' runs, writers do not wait for this token meanwhile
+    fn blocking<R, B: FnOnce() -> R>(&self, id: usize, nest: u32, f: B) -> R {
+        self.qsbr.blocking(id, nest, f)
+    }
+}
+
+impl<T, F: RcuFlavor> RcuGPShared<T, F> {
+    pub fn new(count: u32, data: LinkedList<T>) -> Self {
+        let head_ptr = LinkedNode::alloc(None, std::ptr::null_mut(), std::ptr::null_mut(), 0);
+        let head = unsafe { head_ptr.as_ref().unwrap() };
+        head.next.store(head_ptr, Ordering::Relaxed);
+        head.prev.store(head_ptr, Ordering::Relaxed);
+        for item in data {
+            insert_after(head.prev.load(Ordering::Relaxed), item, 0);
+        }
+        return RcuGPShared {
+            rcu: F::new(count),
+            data_ptr: AtomicPtr::new(head_ptr),
+            writer: Mutex::new(()),
+            version: AtomicU64::new(0),
         };
     }
 }
 
-impl<T> Drop for RcuGPShared<T> {
+impl<T, F: RcuFlavor> Drop for RcuGPShared<T, F> {
     fn drop(&mut self) {
-        let mut cc = 0;
-        let mut current_ptr: *mut LinkedNode<T> = self.data_ptr.load(Ordering::Relaxed);
-        let head_ptr = current_ptr;
-        if head_ptr.is_null() == false {
-            current_ptr = unsafe { current_ptr.as_ref().unwrap().next.load(Ordering::Relaxed) };
-        }
-        while unsafe { current_ptr.as_ref().unwrap().head } == false {
+        let head_ptr = self.data_ptr.load(Ordering::Relaxed);
+        let mut current_ptr = unsafe { head_ptr.as_ref().unwrap().next.load(Ordering::Relaxed) };
+        while current_ptr != head_ptr {
             let next = unsafe { current_ptr.as_ref().unwrap().next.load(Ordering::Relaxed) };
-            let bx = unsafe { Box::from_raw(current_ptr) };
+            let _ = unsafe { Box::from_raw(current_ptr) };
             current_ptr = next;
         }
-
-        let bx: Box<LinkedNode<T>> = unsafe { Box::from_raw(head_ptr) };
+        let _ = unsafe { Box::from_raw(head_ptr) };
     }
 }
 
-unsafe impl<T> Send for RcuGPShared<T> {}
-unsafe impl<T> Sync for RcuGPShared<T> {}
+// readers on many threads share the elements, and any thread may drop them
+unsafe impl<T: Send + Sync, F: RcuFlavor> Send for RcuGPShared<T, F> {}
+unsafe impl<T: Send + Sync, F: RcuFlavor> Sync for RcuGPShared<T, F> {}
 
-pub struct RcuGpReadGuard<'a, T: 'a> {
-    inner_lock: &'a RcuList<T>,
+pub struct RcuGpReadGuard<'a, T: 'a, F: RcuFlavor = GpFlavor> {
+    inner_lock: &'a RcuList<T, F>,
 
     cas_ptr: *mut LinkedNode<T>,
+    version: u64, // the list version this guard sees
 }
 
-impl<'a, T: 'a> RcuGpReadGuard<'a, T> {
-    pub fn new(lock: &'a RcuList<T>) -> Self {
-        let ptr = lock.global_info.data_ptr.load(Ordering::Acquire);
-        return RcuGpReadGuard {
+impl<'a, T: 'a, F: RcuFlavor> RcuGpReadGuard<'a, T, F> {
+    pub fn new(lock: &'a RcuList<T, F>) -> Self {
+        let mut guard = RcuGpReadGuard {
             inner_lock: lock,
-            cas_ptr: ptr,
+            cas_ptr: lock.head(),
+            version: lock.global_info.version.load(Ordering::Acquire),
         };
+        guard.go_next();
+        guard
     }
     fn get_next_ptr(&self) -> *mut LinkedNode<T> {
         if self.cas_ptr.is_null() == false {
@@ -160,17 +362,23 @@ impl<'a, T: 'a> RcuGpReadGuard<'a, T> {
         }
     }
 
-    pub fn get_data(&self) -> Option<&'a T> {
-        if self.cas_ptr.is_null() == false {
-            return Some(unsafe { (*self.cas_ptr).data.get_mut() });
-        } else {
-            return None;
-        }
+    /// The element under the cursor, it borrows the guard so it can not outlive the read section
+    ///
+    /// ```compile_fail,E0505
+    /// use std::collections::LinkedList;
+    /// let list = rcu::rcu_list::RcuList::gen_list(1, LinkedList::from([1u32])).pop().unwrap();
+    /// let guard = list.read();
+    /// let first = guard.get_data();
+    /// drop(guard);
+    /// println!("{:?}", first);
+    /// ```
+    pub fn get_data(&self) -> Option<&T> {
+        unsafe { self.cas_ptr.as_ref() }.and_then(|node| node.data.as_ref())
     }
 
     pub fn go_next(&mut self) {
         if self.cas_ptr.is_null() == false {
-            let ptr = unsafe { (*self.cas_ptr).next.load(Ordering::Acquire) };
+            let ptr = next_visible(self.cas_ptr, self.version);
             if (unsafe { ptr.as_ref().unwrap().head } == false) {
                 self.cas_ptr = ptr;
             } else {
@@ -178,24 +386,174 @@ impl<'a, T: 'a> RcuGpReadGuard<'a, T> {
             }
         }
     }
+
+    // move to the previous node, from the first node this moves past the end
+    // at the end of the list this moves to the last node, so a reverse walk can start from there
+    pub fn go_prev(&mut self) {
+        let ptr = prev_visible(self.cursor_node(), self.version);
+        if !unsafe { ptr.as_ref().unwrap().head } {
+            self.cas_ptr = ptr;
+        } else {
+            self.cas_ptr = std::ptr::null_mut();
+        }
+    }
+
+    // move the cursor back to the first node
+    pub fn go_front(&mut self) {
+        self.cas_ptr = self.inner_lock.head();
+        self.go_next();
+    }
+
+    // move the cursor to the first element that matches 'pred', past the end if none does
+    pub fn find<P: FnMut(&T) -> bool>(&mut self, mut pred: P) -> Option<&T> {
+        self.go_front();
+        while let Some(data) = self.get_data() {
+            if pred(data) {
+                break;
+            }
+            self.go_next();
+        }
+        self.get_data()
+    }
+
+    // move the cursor to the element at 'index', past the end if the list is shorter
+    pub fn nth(&mut self, index: usize) -> Option<&T> {
+        self.go_front();
+        for _ in 0..index {
+            self.go_next();
+        }
+        self.get_data()
+    }
+
+    // iterate over the whole list, from the front or from the back
+    pub fn iter(&self) -> Iter<'_, T> {
+        let head = self.inner_lock.head();
+        Iter {
+            head,
+            front: next_visible(head, self.version),
+            back: prev_visible(head, self.version),
+            front_done: head,
+            back_done: head,
+            version: self.version,
+            _guard: PhantomData,
+        }
+    }
+
+    // the node under the cursor, the sentinel head when the cursor is past the end
+    fn cursor_node(&self) -> *mut LinkedNode<T> {
+        if self.cas_ptr.is_null() {
+            self.inner_lock.head()
+        } else {
+            self.cas_ptr
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let head = self.inner_lock.head();
+        next_visible(head, self.version) == head
+    }
+
+    // number of elements, this walks the whole list
+    pub fn len(&self) -> usize {
+        let head = self.inner_lock.head();
+        let mut count = 0;
+        let mut ptr = next_visible(head, self.version);
+        while ptr != head {
+            count += 1;
+            ptr = next_visible(ptr, self.version);
+        }
+        count
+    }
 }
 
-impl<'a, T> Drop for RcuGpReadGuard<'a, T> {
+impl<'a, T, F: RcuFlavor> Drop for RcuGpReadGuard<'a, T, F> {
     fn drop(&mut self) {
         self.inner_lock.read_unlock();
     }
 }
 
-pub struct RcuGpWriteGuard<'a, T: 'a> {
-    reader: Option<RcuGpReadGuard<'a, T>>,
-    inner_lock: &'a RcuList<T>,
+/*
+Iterator over the elements of a list, it can only live as long as the guard
+Front and back stop when they meet; while a writer is changing the list, they
+stop at the first node the other end has already returned
+ */
+pub struct Iter<'g, T: 'g> {
+    head: *mut LinkedNode<T>,
+    front: *mut LinkedNode<T>, // next node to return from the front
+    back: *mut LinkedNode<T>,  // next node to return from the back
+    front_done: *mut LinkedNode<T>, // last node returned from the front
+    back_done: *mut LinkedNode<T>,  // last node returned from the back
+    version: u64,
+    _guard: PhantomData<&'g T>,
+}
+
+impl<'g, T: 'g> Iter<'g, T> {
+    fn finish(&mut self) {
+        self.front = self.head;
+        self.back = self.head;
+    }
+}
+
+impl<'g, T: 'g> Iterator for Iter<'g, T> {
+    type Item = &'g T;
+
+    fn next(&mut self) -> Option<&'g T> {
+        if self.front == self.head || self.front == self.back_done {
+            self.finish();
+            return None;
+        }
+        let node = unsafe { self.front.as_ref().unwrap() };
+        if self.front == self.back {
+            self.finish();
+        } else {
+            self.front_done = self.front;
+            self.front = next_visible(self.front, self.version);
+        }
+        node.data.as_ref()
+    }
+}
+
+impl<'g, T: 'g> DoubleEndedIterator for Iter<'g, T> {
+    fn next_back(&mut self) -> Option<&'g T> {
+        if self.back == self.head || self.back == self.front_done {
+            self.finish();
+            return None;
+        }
+        let node = unsafe { self.back.as_ref().unwrap() };
+        if self.back == self.front {
+            self.finish();
+        } else {
+            self.back_done = self.back;
+            self.back = prev_visible(self.back, self.version);
+        }
+        node.data.as_ref()
+    }
+}
+
+pub struct RcuGpWriteGuard<'a, T: 'a, F: RcuFlavor = GpFlavor> {
+    reader: Option<RcuGpReadGuard<'a, T, F>>,
+    inner_lock: &'a RcuList<T, F>,
 
     temp: Vec<Box<LinkedNode<T>>>,
 
+    txn: Option<u64>,              // the version published by an atomic write guard
+    dead: Vec<*mut LinkedNode<T>>, // nodes removed by an atomic write guard, still linked
+
+    _writer: MutexGuard<'a, ()>,
 }
 
-impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
-    pub fn get_data(&self) -> Option<&'a T> {
+impl<'a, T: 'a, F: RcuFlavor> RcuGpWriteGuard<'a, T, F> {
+    /// The element under the cursor, it borrows the guard so it can not outlive the write
+    ///
+    /// ```compile_fail,E0505
+    /// use std::collections::LinkedList;
+    /// let list = rcu::rcu_list::RcuList::gen_list(1, LinkedList::from([1u32])).pop().unwrap();
+    /// let guard = list.write();
+    /// let first = guard.get_data();
+    /// drop(guard);
+    /// println!("{:?}", first);
+    /// ```
+    pub fn get_data(&self) -> Option<&T> {
         if (self.reader.is_some()) {
             return self.reader.as_ref().unwrap().get_data();
         } else {
@@ -207,22 +565,54 @@ impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
             self.reader.as_mut().unwrap().go_next();
         }
     }
+
+    // move to the previous node, see RcuGpReadGuard::go_prev()
+    pub fn go_prev(&mut self) {
+        if let Some(reader) = self.reader.as_mut() {
+            reader.go_prev();
+        }
+    }
+
+    // move the cursor back to the first node
+    pub fn go_front(&mut self) {
+        if let Some(reader) = self.reader.as_mut() {
+            reader.go_front();
+        }
+    }
+
+    // move the cursor to the first element that matches 'pred', see RcuGpReadGuard::find()
+    pub fn find<P: FnMut(&T) -> bool>(&mut self, pred: P) -> Option<&T> {
+        self.reader.as_mut().and_then(|reader| reader.find(pred))
+    }
+
+    // move the cursor to the element at 'index', see RcuGpReadGuard::nth()
+    pub fn nth(&mut self, index: usize) -> Option<&T> {
+        self.reader.as_mut().and_then(|reader| reader.nth(index))
+    }
+
+    // iterate over the whole list, including the changes made by this guard
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.reader.as_ref().unwrap().iter()
+    }
     pub fn replace(&mut self, new_data: T) {
         if (self.reader.is_some()) {
             
             let old = self.reader.as_ref().unwrap().cas_ptr;
+            if let (Some(version), false) = (self.txn, old.is_null()) {
+                // readers of the old version still see the old node until the guard drops
+                let new_ptr = insert_after(old, new_data, version);
+                unsafe { old.as_ref().unwrap().death.store(version, Ordering::Relaxed) };
+                self.dead.push(old);
+                if let Some(reader) = self.reader.as_mut() {
+                    reader.cas_ptr = new_ptr;
+                }
+                return;
+            }
             if (old.is_null() == false) {
                 
                 let next = self.reader.as_ref().unwrap().get_next_ptr();
                 let prev = self.reader.as_ref().unwrap().get_prev_ptr();
-                let node = Box::new(LinkedNode {
-                    data: new_data.into(),
-                    next: AtomicPtr::new(next),
-                    prev: AtomicPtr::new(prev),
-                    head: false,
-                });
-                
-                let new_ptr = Box::<LinkedNode<T>>::into_raw(node);
+                let new_ptr = LinkedNode::alloc(Some(new_data), next, prev, 0);
                 unsafe {
                     next.as_mut()
                         .unwrap()
@@ -237,30 +627,149 @@ impl<'a, T: 'a> RcuGpWriteGuard<'a, T> {
             }
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.as_ref().is_none_or(|reader| reader.is_empty())
+    }
+
+    // number of elements, this walks the whole list
+    pub fn len(&self) -> usize {
+        self.reader.as_ref().map_or(0, |reader| reader.len())
+    }
+
+    // the version of the nodes inserted by this guard
+    fn birth(&self) -> u64 {
+        self.txn.unwrap_or(0)
+    }
+
+    // insert at the front of the list, the cursor does not move
+    pub fn push_front(&mut self, new_data: T) {
+        insert_after(self.inner_lock.head(), new_data, self.birth());
+    }
+
+    // insert at the back of the list, the cursor does not move
+    pub fn push_back(&mut self, new_data: T) {
+        let head = self.inner_lock.head();
+        insert_after(unsafe { head.as_ref().unwrap().prev.load(Ordering::Acquire) }, new_data, self.birth());
+    }
+
+    // insert after the cursor, the cursor does not move
+    // at the end of the list this inserts at the front
+    pub fn insert_after(&mut self, new_data: T) {
+        if let Some(reader) = self.reader.as_ref() {
+            insert_after(reader.cursor_node(), new_data, self.birth());
+        }
+    }
+
+    // insert before the cursor, the cursor does not move
+    // at the end of the list this inserts at the back
+    pub fn insert_before(&mut self, new_data: T) {
+        if let Some(reader) = self.reader.as_ref() {
+            let node = reader.cursor_node();
+            insert_after(unsafe { node.as_ref().unwrap().prev.load(Ordering::Acquire) }, new_data, self.birth());
+        }
+    }
+
+    // insert all of 'data' after the cursor, readers see all of it or none of it
+    // at the end of the list this inserts at the front
+    pub fn splice_after_cursor(&mut self, data: LinkedList<T>) {
+        if let (Some(reader), Some(chain)) = (self.reader.as_ref(), build_chain(data, self.birth())) {
+            splice_after(reader.cursor_node(), chain);
+        }
+    }
+
+    // insert all of 'data' at the back of the list, readers see all of it or none of it
+    pub fn splice_back(&mut self, data: LinkedList<T>) {
+        if let Some(chain) = build_chain(data, self.birth()) {
+            let head = self.inner_lock.head();
+            splice_after(unsafe { head.as_ref().unwrap().prev.load(Ordering::Acquire) }, chain);
+        }
+    }
+
+    // unlink the node under the cursor, the cursor moves to the next node
+    // the removed node keeps its links, so a reader parked on it can still go_next back into the list
+    // returns false if the cursor is at the end of the list
+    pub fn remove_current(&mut self) -> bool {
+        if let Some(reader) = self.reader.as_mut() {
+            let old = reader.cas_ptr;
+            if !old.is_null() {
+                if let Some(version) = self.txn {
+                    // readers of the old version still see the node until the guard drops
+                    unsafe { old.as_ref().unwrap().death.store(version, Ordering::Relaxed) };
+                    reader.go_next();
+                    self.dead.push(old);
+                } else {
+                    unlink(old);
+                    reader.go_next();
+                    self.temp.push(unsafe { Box::from_raw(old) });
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    // finish the write and get the removed and replaced data back
+    // this will result in a synchronize_rcu() if a node was removed or replaced
+    pub fn get_old(mut self) -> Vec<T> {
+        self.finish();
+        std::mem::take(&mut self.temp).into_iter().filter_map(|bx| bx.data).collect()
+    }
+
+    // leave the read-side critical section, publish the version of an atomic write guard,
+    // and wait until no reader can see the removed nodes
+    fn finish(&mut self) {
+        self.reader = None;
+        if let Some(version) = self.txn.take() {
+            // readers that start from now on see all the changes of this guard
+            self.inner_lock.global_info.version.store(version, Ordering::Release);
+            // wait for the readers of the old version even if nothing was removed,
+            // the next normal write guard would change the list under them
+            self.inner_lock.synchronize_rcu();
+            for ptr in self.dead.drain(..) {
+                unlink(ptr);
+                self.temp.push(unsafe { Box::from_raw(ptr) });
+            }
+        }
+        // inserting does not retire any node
+        if !self.temp.is_empty() {
+            self.inner_lock.synchronize_rcu();
+        }
+    }
 }
 
-pub enum CasResult<'a, T: 'a> {
-    Guard(RcuGpWriteGuard<'a, T>),
+pub enum CasResult<'a, T: 'a, F: RcuFlavor = GpFlavor> {
+    Guard(RcuGpWriteGuard<'a, T, F>),
     Old(T),
 }
 
-impl<'a, T> Drop for RcuGpWriteGuard<'a, T> {
+impl<'a, T, F: RcuFlavor> Drop for RcuGpWriteGuard<'a, T, F> {
     fn drop(&mut self) {
-        {
-            //println!("{}",self.temp.len());
-            self.reader = None;
-            //
-        }
-        self.inner_lock.synchronize_rcu();
+        self.finish();
     }
 }
 
-pub struct RcuList<T> {
+/// A token is Send but not Sync, each thread needs its own,
+/// and the elements have to be Send and Sync as well
+///
+/// ```compile_fail,E0277
+/// use std::collections::LinkedList;
+/// let list = rcu::rcu_list::RcuList::gen_list(1, LinkedList::from([std::rc::Rc::new(0u32)])).pop().unwrap();
+/// std::thread::spawn(move || list.read().iter().count());
+/// ```
+pub struct RcuList<T, F: RcuFlavor = GpFlavor> {
     thread_id: usize,
 
-    global_info: Arc<RcuGPShared<T>>,
+    global_info: Arc<RcuGPShared<T, F>>,
+
+    owner: OwnerCheck,
+
+    online: Cell<bool>, // mirrors the QSBR slot, so reads do not load it
 }
 
+// A list on QSBR, reads take no counter but every token has to call quiescent_state() regularly
+pub type RcuQsbrList<T> = RcuList<T, QsbrFlavor>;
+
 fn is_busy(ctr: &AtomicU32, global_ctr: u32) -> bool {
     let value = ctr.load(Ordering::Relaxed);
     return ((value & RCU_NEST_MASK) != 0) && (((value ^ global_ctr) & RCU_GP_CTR_PHASE) != 0);
@@ -268,6 +777,12 @@ fn is_busy(ctr: &AtomicU32, global_ctr: u32) -> bool {
 
 impl<T> RcuList<T> {
     pub fn gen_list(num: u32, data: LinkedList<T>) -> Vec<Self> {
+        Self::gen_tokens(num, data)
+    }
+}
+
+impl<T, F: RcuFlavor> RcuList<T, F> {
+    fn gen_tokens(num: u32, data: LinkedList<T>) -> Vec<Self> {
         let shared = Arc::new(RcuGPShared::new(num, data));
 
         let mut r = Vec::new();
@@ -279,80 +794,227 @@ impl<T> RcuList<T> {
         return r;
     }
 
-    fn new(shared: Arc<RcuGPShared<T>>) -> Self {
-        let tc = shared.thread_counter.fetch_add(1, Ordering::SeqCst) * CACHE_RATE;
+    // the sentinel head node, it never changes and is never exposed as data
+    fn head(&self) -> *mut LinkedNode<T> {
+        self.global_info.data_ptr.load(Ordering::Acquire)
+    }
+
+    fn new(shared: Arc<RcuGPShared<T, F>>) -> Self {
+        let tc = shared.rcu.register();
 
         return RcuList {
-            thread_id: tc as usize,
+            thread_id: tc,
             global_info: shared,
+            owner: OwnerCheck::new(),
+            online: Cell::new(true),
         };
     }
 
-    pub fn read(&self) -> RcuGpReadGuard<'_, T> {
+    pub fn read(&self) -> RcuGpReadGuard<'_, T, F> {
         self.read_lock();
 
         return RcuGpReadGuard::new(self);
     }
 
-    pub fn write(&self) -> RcuGpWriteGuard<'_, T> {
+    // create a write guard, this blocks while another token holds one
+    // a read guard of this token would hang the current writer and this call, see try_write()
+    pub fn write(&self) -> RcuGpWriteGuard<'_, T, F> {
+        debug_assert!(self.owner.depth() == 0, "{}", RcuGpError::SelfDeadlock);
+        // take the writer lock before entering the read-side critical section,
+        // the current writer may be waiting for our readers in synchronize_rcu()
+        let writer = self
+            .global_info
+            .rcu
+            .blocking(self.thread_id, self.owner.depth(), || {
+                // the lock guards no data, a closure that panicked under it leaves nothing broken
+                self.global_info.writer.lock().unwrap_or_else(PoisonError::into_inner)
+            });
         return RcuGpWriteGuard {
             reader: Some(self.read()),
             inner_lock: self,
             temp: Vec::new(),
+            txn: None,
+            dead: Vec::new(),
+            _writer: writer,
         };
     }
 
-    fn read_lock(&self) {
-        //println!("read");
-        let id = self.thread_id;
-        let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
+    // create a write guard, fails if a read guard of this token is alive,
+    // the write guard would wait for it when it drops
+    pub fn try_write(&self) -> Result<RcuGpWriteGuard<'_, T, F>, RcuGpError> {
+        if self.owner.depth() != 0 {
+            return Err(RcuGpError::SelfDeadlock);
+        }
+        Ok(self.write())
+    }
 
-        if (temp_local & RCU_NEST_MASK) == 0 {
-            let global = self.global_info.global_ctr.load(Ordering::Acquire);
-            self.global_info.thread_ctr[id].store(global + RCU_NEST_COUNT, Ordering::SeqCst);
+    // create a write guard whose changes become visible all at once when it drops
+    // readers see either the whole list before the write or the whole list after it
+    // it always waits for a grace period, removing or replacing nodes costs a second one
+    pub fn write_atomic(&self) -> RcuGpWriteGuard<'_, T, F> {
+        let mut guard = self.write();
+        let version = self.global_info.version.load(Ordering::Acquire) + 1;
+        guard.txn = Some(version);
+        guard.reader.as_mut().unwrap().version = version;
+        guard
+    }
 
-            smp_mb();
-        } else {
-            self.global_info.thread_ctr[id].store(temp_local + RCU_NEST_COUNT, Ordering::Relaxed)
-            //rlocal_ctr[id].store(global_ctr.read(Ordering::Acquire),Ordering::Release );
-        }
+    // create a write guard with the cursor on the first element that matches 'pred'
+    // the cursor is past the end if no element matches
+    pub fn write_at<P: FnMut(&T) -> bool>(&self, pred: P) -> RcuGpWriteGuard<'_, T, F> {
+        let mut guard = self.write();
+        guard.find(pred);
+        guard
     }
 
-    fn read_unlock(&self) {
-        //println!("read unlock");
-        smp_mb();
-        let id = self.thread_id;
-        let temp_local = self.global_info.thread_ctr[id].load(Ordering::Acquire);
-        self.global_info.thread_ctr[id].store(temp_local - RCU_NEST_COUNT, Ordering::SeqCst)
+    // insert all of 'data' at the back of the list, readers see all of it or none of it
+    pub fn splice_back(&self, data: LinkedList<T>) {
+        self.write().splice_back(data);
     }
 
-    fn synchronize_rcu(&self) {
-        //println!("synchronize_rcu");
-        smp_mb();
-        {
-            let _lg = self.global_info.data.lock().unwrap();
-            self.update_counter_and_wait();
-            barrier();
-            self.update_counter_and_wait();
+    // move all the elements of 'other' to the back of this list
+    // readers of this list see all of them or none of them,
+    // readers of 'other' see the old list or an empty one
+    pub fn append(&self, other: &mut RcuList<T, F>) {
+        if Arc::ptr_eq(&self.global_info, &other.global_info) {
+            return;
+        }
+        if let Some(chain) = other.take_chain() {
+            let _guard = self.write();
+            let head = self.head();
+            splice_after(unsafe { head.as_ref().unwrap().prev.load(Ordering::Acquire) }, chain);
         }
-        smp_mb();
     }
 
-    fn update_counter_and_wait(&self) {
-        let old_value: u32 = self.global_info.global_ctr.load(Ordering::Acquire);
-        let new_value: u32 = old_value ^ RCU_GP_CTR_PHASE;
-        self.global_info
-            .global_ctr
-            .store(new_value, Ordering::Release);
-        barrier();
-        let mut count = 0;
-        for ctr in &self.global_info.thread_ctr {
-            if count % CACHE_RATE == 0 {
-                while is_busy(ctr, new_value) {
-                    std::thread::yield_now();
-                }
+    // unlink all the nodes, they can be linked into another list after this returns
+    fn take_chain(&self) -> Option<Chain<T>> {
+        let chain = {
+            let _guard = self.write();
+            let head = self.head();
+            let head_ref = unsafe { head.as_ref().unwrap() };
+            let first = head_ref.next.load(Ordering::Acquire);
+            if first == head {
+                return None;
+            }
+            let last = head_ref.prev.load(Ordering::Acquire);
+            head_ref.next.store(head, Ordering::Release);
+            head_ref.prev.store(head, Ordering::Release);
+            (first, last)
+        };
+        // readers of this list may still walk the chain
+        self.synchronize_rcu();
+        // the versions of this list mean nothing in another list
+        let mut ptr = chain.0;
+        loop {
+            let node = unsafe { ptr.as_ref().unwrap() };
+            node.birth.store(0, Ordering::Relaxed);
+            if ptr == chain.1 {
+                break;
+            }
+            ptr = node.next.load(Ordering::Relaxed);
+        }
+        Some(chain)
+    }
+
+    // remove the elements for which 'keep' returns false
+    // all the removed nodes are dropped after one synchronize_rcu()
+    pub fn retain<K: FnMut(&T) -> bool>(&self, mut keep: K) {
+        let mut guard = self.write();
+        while let Some(data) = guard.get_data() {
+            if keep(data) {
+                guard.go_next();
+            } else {
+                guard.remove_current();
             }
-            count += 1;
         }
     }
+
+    // replace every element with 'update' of it
+    // all the old nodes are dropped after one synchronize_rcu()
+    pub fn update_all<U: FnMut(&T) -> T>(&self, mut update: U) {
+        let mut guard = self.write();
+        while let Some(data) = guard.get_data() {
+            let new_data = update(data);
+            guard.replace(new_data);
+            guard.go_next();
+        }
+    }
+
+    // remove all the elements, they are dropped after one synchronize_rcu()
+    pub fn clear(&self) {
+        let mut guard = self.write();
+        while guard.remove_current() {}
+    }
+
+    // copy the current elements under one read-side critical section
+    // the copy can be processed without holding a read guard
+    pub fn snapshot(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.read().iter().cloned().collect()
+    }
+
+    // append a copy of the current elements to 'out', under one read-side critical section
+    pub fn collect_into(&self, out: &mut LinkedList<T>)
+    where
+        T: Clone,
+    {
+        out.extend(self.read().iter().cloned());
+    }
+
+    fn read_lock(&self) {
+        self.global_info.rcu.read_lock(self.thread_id, self.owner.depth(), self.online.get());
+        self.online.set(true);
+        self.owner.enter();
+    }
+
+    fn read_unlock(&self) {
+        self.owner.exit();
+        self.global_info.rcu.read_unlock(self.thread_id);
+    }
+
+    fn synchronize_rcu(&self) {
+        self.global_info.rcu.synchronize_rcu(self.thread_id, self.owner.depth());
+    }
+}
+
+impl<T, F: RcuFlavor> Drop for RcuList<T, F> {
+    fn drop(&mut self) {
+        self.global_info.rcu.unregister(self.thread_id);
+    }
+}
+
+impl<T> RcuList<T, QsbrFlavor> {
+    // the tokens start online, see quiescent_state()
+    pub fn gen_qsbr_list(num: u32, data: LinkedList<T>) -> Vec<Self> {
+        Self::gen_tokens(num, data)
+    }
+
+    // announce that this token holds no reference into the list
+    // it takes '&mut self' so no guard of this token can be alive
+    pub fn quiescent_state(&mut self) {
+        self.global_info.rcu.qsbr.quiescent_state(self.thread_id, self.owner.depth());
+    }
+
+    /// Go offline, writers do not wait for this token until thread_online().
+    /// It takes '&mut self' so no guard of this token can be alive.
+    /// read() while offline panics in debug builds, release builds silently bring the token back online
+    ///
+    /// ```compile_fail,E0502
+    /// use std::collections::LinkedList;
+    /// let mut list = rcu::rcu_list::RcuList::gen_qsbr_list(1, LinkedList::from([1u32])).pop().unwrap();
+    /// let guard = list.read();
+    /// list.thread_offline();
+    /// println!("{:?}", guard.get_data());
+    /// ```
+    pub fn thread_offline(&mut self) {
+        self.global_info.rcu.qsbr.thread_offline(self.thread_id, self.owner.depth());
+        self.online.set(false);
+    }
+
+    pub fn thread_online(&self) {
+        self.global_info.rcu.qsbr.thread_online(self.thread_id, self.owner.depth());
+        self.online.set(true);
+    }
 }
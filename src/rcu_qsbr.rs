@@ -1,30 +1,172 @@
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
 use std::ops::Deref;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicU32, Ordering, AtomicPtr, fence};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering, AtomicPtr, fence};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
+use std::thread::JoinHandle;
 
 use std::sync::Mutex;
 
-const RCU_GP_ONLINE: u32 = 0x1;
+use crate::owner::OwnerCheck;
+use crate::RcuGpError;
+
 const RCU_GP_CTR: u32 = 0x2;
 
+/// A token is Send but not Sync, each thread needs its own,
+/// and the protected data has to be Send and Sync as well
+///
+/// ```compile_fail,E0277
+/// let token = rcu::rcu_qsbr::RcuQsbr::gen_tokens(1, std::cell::Cell::new(0u32)).pop().unwrap();
+/// std::thread::spawn(move || token.read().get());
+/// ```
 pub struct RcuQsbr<T> {
     thread_id: usize,
     global_info: Arc<RcuQsbrShared<T>>,
+    owner: OwnerCheck,
+    online: Cell<bool>, // mirrors the slot, so reads do not load it
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcuQsbrError {
+    NoFreeSlot, // all the reader slots are taken by live tokens
+    Offline,    // the token is offline, writers do not wait for its readers
+}
+
+impl std::fmt::Display for RcuQsbrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RcuQsbrError::NoFreeSlot => write!(f, "no free reader slot in RcuQsbrShared"),
+            RcuQsbrError::Offline => write!(f, "read() on an offline QSBR token"),
+        }
+    }
 }
 
+impl std::error::Error for RcuQsbrError {}
+
 pub fn barrier() { fence(Ordering::SeqCst); }
 pub fn smp_mb() { fence(Ordering::SeqCst); }
 
-pub struct RcuQsbrShared<T> {
-    thread_counter: AtomicU32,
+/*
+The reader slots and grace periods of QSBR, shared by RcuQsbr and the QSBR flavor of RcuList
+A slot is 0 while its token is offline, otherwise the global counter at its last quiescent state.
+Reads touch no shared memory, the token keeps whether it is online and how many of its read guards
+are alive, and passes them in. A token never goes offline or reports a quiescent state under a read guard.
+ */
+pub(crate) struct QsbrState {
+    slot_used: Vec<AtomicBool>, // a token owns the slot while it is alive
 
     global_ctr: AtomicU32,
 
     thread_ctr: Vec<AtomicU32>,
 
     mtx: Mutex<i32>,
+}
+
+impl QsbrState {
+    pub(crate) fn new(count: usize) -> Self {
+        let mut my_vec = Vec::new();
+        let mut used = Vec::new();
+        for _r in 0..count {
+            // a free slot is offline, writers do not wait for it
+            my_vec.push(AtomicU32::new(0));
+            used.push(AtomicBool::new(false));
+        }
+        QsbrState {
+            slot_used: used,
+            global_ctr: AtomicU32::new(RCU_GP_CTR),
+            thread_ctr: my_vec,
+            mtx: Mutex::new(0),
+        }
+    }
+
+    // claim a free reader slot, the token starts online
+    // slots are given back when their token drops
+    pub(crate) fn alloc_slot(&self) -> Result<usize, RcuQsbrError> {
+        for (id, used) in self.slot_used.iter().enumerate() {
+            if used.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                self.thread_online(id, 0);
+                return Ok(id);
+            }
+        }
+        Err(RcuQsbrError::NoFreeSlot)
+    }
+
+    // the token is dropped, none of its guards is alive
+    pub(crate) fn free_slot(&self, id: usize) {
+        self.thread_offline(id, 0);
+        self.slot_used[id].store(false, Ordering::Release);
+    }
+
+    // reading on an offline token is a bug, it panics in debug builds
+    // release builds silently bring the token back online, nothing reports the bug
+    pub(crate) fn read_lock(&self, id: usize, nest: u32, online: bool) {
+        debug_assert!(online, "{}", RcuQsbrError::Offline);
+        if !online {
+            self.thread_online(id, nest);
+        }
+    }
+
+    pub(crate) fn is_online(&self, id: usize) -> bool {
+        self.thread_ctr[id].load(Ordering::Relaxed) != 0
+    }
+
+    // announce that the token holds no reference to the shared data
+    pub(crate) fn quiescent_state(&self, id: usize, nest: u32) {
+        assert!(nest == 0, "quiescent state inside a read-side critical section of the same token");
+        smp_mb();
+        let v = self.global_ctr.load(Ordering::SeqCst);
+        self.thread_ctr[id].store(v, Ordering::SeqCst);
+        smp_mb();
+    }
+
+    // coming online is a quiescent state as well
+    pub(crate) fn thread_online(&self, id: usize, nest: u32) {
+        self.quiescent_state(id, nest);
+    }
+
+    pub(crate) fn thread_offline(&self, id: usize, nest: u32) {
+        assert!(nest == 0, "offline inside a read-side critical section of the same token");
+        smp_mb();
+        self.thread_ctr[id].store(0, Ordering::SeqCst);
+    }
+
+    // run 'f' offline, writers do not wait for the token while it blocks
+    // the token must not hold a read guard, writers would free the data under it
+    pub(crate) fn blocking<R, B: FnOnce() -> R>(&self, id: usize, nest: u32, f: B) -> R {
+        let was_online = self.is_online(id);
+        if was_online {
+            self.thread_offline(id, nest);
+        }
+        let r = f();
+        if was_online {
+            self.thread_online(id, nest);
+        }
+        r
+    }
+
+    // wait until every online token has passed a quiescent state
+    // a read guard of the token itself would never end, so it is refused
+    pub(crate) fn synchronize_rcu(&self, id: usize, nest: u32) {
+        assert!(nest == 0, "{}", RcuGpError::SelfDeadlock);
+        self.blocking(id, nest, || {
+            let _mtx = self.mtx.lock().unwrap();
+            self.global_ctr.fetch_add(RCU_GP_CTR, Ordering::SeqCst);
+            barrier();
+            for ctr in &self.thread_ctr {
+                let mut v = ctr.load(Ordering::SeqCst);
+                let global_ctr = self.global_ctr.load(Ordering::Relaxed);
+                while v != 0 && v != global_ctr {
+                    std::thread::yield_now();
+                    v = ctr.load(Ordering::SeqCst);
+                }
+            }
+        })
+    }
+}
+
+pub struct RcuQsbrShared<T> {
+    qsbr: QsbrState,
 
     data_ptr : AtomicPtr<T>,
     data: Mutex<Box<UnsafeCell<T>>>,
@@ -35,13 +177,27 @@ pub struct RcuQsbrReadGuard<'a, T: 'a> {
     // `Ref` argument doesn't hold immutability for its whole scope, only until it drops.
     data: NonNull<T>,
     inner_lock: &'a RcuQsbr<T>,
+
+    cas_ptr: *mut T,
 }
 
 pub struct RcuQsbrWriteGuard<'a, T: 'a> {
-    data: Box<UnsafeCell<T>>,
+    data: Option<Box<UnsafeCell<T>>>,
     inner_lock: &'a RcuQsbr<T>,
 }
 
+pub enum CasResult<'a, T: 'a> {
+    Guard(RcuQsbrWriteGuard<'a, T>),
+    Old(T),
+}
+
+
+// The token is offline while this guard is alive, and back online when it drops
+// it borrows the token mutably, so the token can not read while it is offline
+pub struct OfflineGuard<'a, T: 'a> {
+    was_online: bool, // a token which was already offline stays offline
+    inner_lock: &'a mut RcuQsbr<T>,
+}
 
 impl<T> Deref for RcuQsbrReadGuard<'_, T> {
     type Target = T;
@@ -53,22 +209,35 @@ impl<T> Deref for RcuQsbrReadGuard<'_, T> {
 
 impl <'a, T: 'a> Drop for RcuQsbrReadGuard<'a, T> {
     fn drop(&mut self) {
+        // no counter writes, the thread announces its quiescent states with quiescent_state()
         self.inner_lock.read_unlock();
-        self.inner_lock.quiescent_state();
     }
 }
 
+impl<'a, T> Drop for OfflineGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.was_online {
+            self.inner_lock.thread_online();
+        }
+    }
+}
+
+// delete the old data if the get_old is not called
 impl<'a, T> Drop for RcuQsbrWriteGuard<'a, T> {
     fn drop(&mut self) {
-        self.inner_lock.synchronize_rcu();
+        if self.data.is_some() {
+            self.inner_lock.synchronize_rcu();
+        }
     }
 }
 
 impl<'a, T: 'a> RcuQsbrReadGuard<'a, T> {
     pub fn new(lock: &'a RcuQsbr<T>) -> Self {
+        let ptr = lock.global_info.data_ptr.load(Ordering::Acquire);
         return RcuQsbrReadGuard {
-            data: unsafe { NonNull::new_unchecked(lock.global_info.data_ptr.load(Ordering::Acquire)) },
+            data: unsafe { NonNull::new_unchecked(ptr) },
             inner_lock: lock,
+            cas_ptr: ptr,
         };
     }
 }
@@ -83,23 +252,53 @@ impl<'a, T: 'a> RcuQsbrWriteGuard<'a, T> {
         lock.global_info.data_ptr.store( mtx.as_mut().get(), Ordering::SeqCst);
         return RcuQsbrWriteGuard {
             inner_lock: lock,
-            data: old
+            data: Some(old)
         };
     }
+
+    // for atomic reader, only replace the data 'rg' was reading
+    pub fn cas(lock: &'a RcuQsbr<T>, new_data: T, rg: RcuQsbrReadGuard<'a, T>) -> CasResult<'a, T> {
+        // the old data can not be freed before this thread reports a quiescent state
+        let old_ptr = rg.cas_ptr;
+        drop(rg);
+        let mut mtx = lock.global_info.data.lock().unwrap();
+        let bx: Box<UnsafeCell<T>> = Box::new(new_data.into());
+        let r = lock.global_info.data_ptr.compare_exchange(
+            old_ptr,
+            bx.get(),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        match r {
+            Ok(_) => {
+                let old = std::mem::replace(&mut *mtx, bx);
+                CasResult::Guard(RcuQsbrWriteGuard {
+                    inner_lock: lock,
+                    data: Some(old),
+                })
+            }
+            Err(_) => CasResult::Old(bx.into_inner()),
+        }
+    }
+
+    // Get the old protected data
+    // this will result in a synchronize_rcu()
+    pub fn get_old(&mut self) -> Option<T> {
+        let old = self.data.take()?;
+        self.inner_lock.synchronize_rcu();
+        Some(old.into_inner())
+    }
 }
 
+// readers on many threads share the data, and any thread may drop it
+unsafe impl<T: Send + Sync> Send for RcuQsbrShared<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuQsbrShared<T> {}
+
 impl<T> RcuQsbrShared<T> {
     pub fn new(count: i32, data: T) -> Self {
-        let mut my_vec = Vec::new();
-        for _r in 0..count {
-            my_vec.push(AtomicU32::new(RCU_GP_CTR));
-        }
         let mut bx : Box<UnsafeCell<T>> = Box::new(data.into());
         return RcuQsbrShared {
-            thread_counter: AtomicU32::new(0),
-            global_ctr: AtomicU32::new(2),
-            thread_ctr: my_vec,
-            mtx: Mutex::new(0),
+            qsbr: QsbrState::new(count.try_into().unwrap()),
             data_ptr: AtomicPtr:: new(bx.as_mut().get_mut()) ,
             data: Mutex::new(bx),
         };
@@ -109,85 +308,200 @@ impl<T> RcuQsbrShared<T> {
 
 
 impl<T> RcuQsbr<T> {
-    pub fn new(shared: Arc<RcuQsbrShared<T>>) -> Self {
-        let tc = shared.thread_counter.fetch_add(1, Ordering::SeqCst);
-        let tmp = RcuQsbr {
-            thread_id: tc as usize,
+    // generate 'num' tokens sharing 'data', one for each thread
+    pub fn gen_tokens(num: u32, data: T) -> Vec<Self> {
+        let shared = Arc::new(RcuQsbrShared::new(num.try_into().unwrap(), data));
+        (0..num).map(|_| Self::new(shared.clone()).unwrap()).collect()
+    }
+
+    // create a token in a free slot of 'shared'
+    // fails if all the slots are taken by live tokens
+    pub fn new(shared: Arc<RcuQsbrShared<T>>) -> Result<Self, RcuQsbrError> {
+        let id = shared.qsbr.alloc_slot()?;
+        Ok(RcuQsbr {
+            thread_id: id,
             global_info: shared,
-        };
-        tmp.thread_online();
-        return tmp;
+            owner: OwnerCheck::new(),
+            online: Cell::new(true),
+        })
+    }
+
+    // create another token for the same data, in the slot of a dropped token
+    pub fn register(&self) -> Result<Self, RcuQsbrError> {
+        Self::new(self.global_info.clone())
     }
 
     fn read_lock(&self) {
-        // do nothing
+        // no shared memory is written, only the nesting of this token
+        self.global_info.qsbr.read_lock(self.thread_id, self.owner.depth(), self.online.get());
+        self.online.set(true);
+        self.owner.enter();
     }
 
     fn read_unlock(&self) {
-        // do nothing
+        self.owner.exit();
     }
 
+    // panics if a read guard of this token is alive, it would never end
     fn synchronize_rcu(&self) {
-        let id = self.thread_id;
-        let mut was_online: u32 = self.global_info.thread_ctr[id].load(Ordering::Acquire);
-        if was_online != 0 {
-            self.global_info.thread_ctr[id].store(0, Ordering::Relaxed);
-        }
-        {
-            let _mtx = self.global_info.mtx.lock().unwrap();
-            self.update_counter_and_wait();
-        }
-        if was_online != 0 {
-            let v = self.global_info.global_ctr.load(Ordering::Acquire);
-            self.global_info.thread_ctr[id].store(v, Ordering::Relaxed);
-        }
+        self.global_info.qsbr.synchronize_rcu(self.thread_id, self.owner.depth());
     }
 
+    // wait until every online token has passed a quiescent state
     pub fn update_counter_and_wait(&self) {
-        self.global_info.global_ctr.fetch_add(RCU_GP_CTR, Ordering::SeqCst);
-        barrier();
-        self.quiescent_state();
-        barrier();
-        let mut cnt = 0;
-        for ctr in &self.global_info.thread_ctr {
-            let mut v = ctr.load(Ordering::SeqCst);
-            let global_ctr = self.global_info.global_ctr.load(Ordering::Relaxed);
-            while v!=0 && v != global_ctr {
-                std::thread::yield_now();
-                v = ctr.load(Ordering::SeqCst);
-            }
-            cnt += 1;
-        }
+        self.synchronize_rcu();
     }
 
-    fn quiescent_state(&self) {
-        smp_mb();
-        let id = self.thread_id;
-        let v = self.global_info.global_ctr.load(Ordering::SeqCst);
-        self.global_info.thread_ctr[id].store(v, Ordering::SeqCst);
-        smp_mb();
+    // announce that this thread holds no reference to the shared data
+    // call it at the quiescent points of the application, e.g. at the end of each event loop iteration
+    // it takes '&mut self' so no read guard of this token can be alive
+    pub fn quiescent_state(&mut self) {
+        self.global_info.qsbr.quiescent_state(self.thread_id, self.owner.depth());
     }
 
+    // create a read guard
+    // reading on an offline token is a bug, it panics in debug builds
+    // release builds silently bring the token back online and read, no error is reported,
+    // the token stays online after the guard drops. try_read() returns the error instead
     pub fn read(&self) -> RcuQsbrReadGuard<'_, T> {
         self.read_lock();
-        return RcuQsbrReadGuard::new(self);
+        RcuQsbrReadGuard::new(self)
+    }
+
+    // create a read guard, fails if the token is offline
+    pub fn try_read(&self) -> Result<RcuQsbrReadGuard<'_, T>, RcuQsbrError> {
+        if !self.is_online() {
+            return Err(RcuQsbrError::Offline);
+        }
+        self.read_lock();
+        Ok(RcuQsbrReadGuard::new(self))
     }
 
     pub fn replace(&self, new_data: T) -> RcuQsbrWriteGuard<'_, T> {
         return RcuQsbrWriteGuard::new(self, new_data);
     }
 
+    // replace the data only if it is still the one 'rg' was reading
+    pub fn atomic_replace<'a>(&'a self, new_data: T, rg: RcuQsbrReadGuard<'a, T>) -> CasResult<'a, T> {
+        RcuQsbrWriteGuard::cas(self, new_data, rg)
+    }
+
     pub fn thread_online(&self) {
-        self.global_info.thread_ctr[self.thread_id].store(RCU_GP_ONLINE, Ordering::SeqCst);
+        self.global_info.qsbr.thread_online(self.thread_id, self.owner.depth());
+        self.online.set(true);
     }
 
-    pub fn thread_offline(&self) {
-        self.global_info.thread_ctr[self.thread_id].store(0, Ordering::SeqCst);
+    // it takes '&mut self' so no read guard of this token can be alive
+    pub fn thread_offline(&mut self) {
+        self.global_info.qsbr.thread_offline(self.thread_id, self.owner.depth());
+        self.online.set(false);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.get()
+    }
+
+    /// Go offline until the guard drops, e.g. around blocking I/O.
+    /// The token can not read while it is offline
+    ///
+    /// ```compile_fail,E0502
+    /// let mut token = rcu::rcu_qsbr::RcuQsbr::gen_tokens(1, 0u32).pop().unwrap();
+    /// let guard = token.read();
+    /// token.offline_scope(|| ());
+    /// println!("{}", *guard);
+    /// ```
+    pub fn offline(&mut self) -> OfflineGuard<'_, T> {
+        let was_online = self.is_online();
+        if was_online {
+            self.thread_offline();
+        }
+        OfflineGuard {
+            was_online,
+            inner_lock: self,
+        }
+    }
+
+    // run 'f' offline, writers do not wait for this thread while it blocks
+    pub fn offline_scope<R, F: FnOnce() -> R>(&mut self, f: F) -> R {
+        let _guard = self.offline();
+        f()
     }
 }
 
 impl<T> Drop for RcuQsbr<T> {
     fn drop(&mut self) {
-        self.thread_offline();
+        self.global_info.qsbr.free_slot(self.thread_id);
+    }
+}
+
+type Task<T> = Box<dyn FnOnce(&RcuQsbr<T>) + Send + 'static>;
+
+// Worker threads that each own a token of the shared data
+// a worker is offline while it waits for a task and reports a quiescent state before each task
+pub struct RcuQsbrPool<T> {
+    sender: Option<Sender<Task<T>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + Sync + 'static> RcuQsbrPool<T> {
+    pub fn new(workers: u32, data: T) -> Self {
+        let (sender, receiver) = channel::<Task<T>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = RcuQsbr::gen_tokens(workers, data)
+            .into_iter()
+            .map(|token| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || Self::worker(token, receiver))
+            })
+            .collect();
+        RcuQsbrPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    // run 'task' on one of the workers, it can read() and replace() with the worker's token
+    // a panicking task ends its worker, panics if no worker is left
+    pub fn execute<F: FnOnce(&RcuQsbr<T>) + Send + 'static>(&self, task: F) {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Box::new(task))
+            .expect("every worker of the pool has panicked");
+    }
+
+    fn worker(mut token: RcuQsbr<T>, receiver: Arc<Mutex<Receiver<Task<T>>>>) {
+        loop {
+            let task = {
+                // idle, writers do not wait for this thread
+                let _offline = token.offline();
+                let receiver = receiver.lock().unwrap();
+                receiver.recv()
+            };
+            // coming back online reported a quiescent state, the previous task holds no reference anymore
+            match task {
+                Ok(task) => task(&token),
+                Err(_) => break, // the pool is dropped
+            }
+        }
+    }
+}
+
+// finish the queued tasks and join the workers
+// the panic of a task is raised again here, unless the pool is dropped while unwinding
+impl<T> Drop for RcuQsbrPool<T> {
+    fn drop(&mut self) {
+        self.sender = None;
+        let mut panic = None;
+        for worker in self.workers.drain(..) {
+            if let Err(payload) = worker.join() {
+                panic.get_or_insert(payload);
+            }
+        }
+        if let Some(payload) = panic {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(payload);
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,37 @@
+use std::collections::LinkedList;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use rcu::rcu_list::RcuList;
+
+// a reader that started before an insert-only write_atomic must not lose elements
+// to a normal write that follows it
+#[test]
+fn old_reader_keeps_its_snapshot_across_atomic_and_normal_writes() {
+    let mut tokens = RcuList::gen_list(2, LinkedList::from([1, 2, 3])).into_iter();
+    let writer = tokens.next().unwrap();
+    let reader = tokens.next().unwrap();
+
+    let (ready, started) = channel();
+    let handle = thread::spawn(move || {
+        let guard = reader.read();
+        ready.send(()).unwrap();
+        // give the writer time to run both guards while this one is alive
+        thread::sleep(Duration::from_millis(100));
+        let seen: Vec<i32> = guard.iter().copied().collect();
+        drop(guard);
+        seen
+    });
+    started.recv().unwrap();
+
+    writer.write_atomic().push_back(4);
+    {
+        let mut guard = writer.write();
+        guard.nth(1);
+        guard.replace(20);
+    }
+
+    assert_eq!(handle.join().unwrap(), vec![1, 2, 3]);
+    assert_eq!(writer.read().iter().copied().collect::<Vec<_>>(), vec![1, 20, 3, 4]);
+}